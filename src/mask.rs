@@ -1,4 +1,5 @@
 use bevy::{
+    core_pipeline::core_3d::ViewDepthTexture,
     pbr::{MeshPipeline, MeshPipelineKey, MAX_CASCADES_PER_LIGHT, MAX_DIRECTIONAL_LIGHTS},
     prelude::*,
     render::{
@@ -6,9 +7,11 @@ use bevy::{
         render_graph::{Node, RenderGraphContext, SlotInfo, SlotType},
         render_phase::RenderPhase,
         render_resource::{
-            ColorTargetState, ColorWrites, FragmentState, LoadOp, MultisampleState, Operations,
-            RenderPassColorAttachment, RenderPassDescriptor, RenderPipelineDescriptor,
-            ShaderDefVal, SpecializedMeshPipeline, SpecializedMeshPipelineError, TextureFormat,
+            ColorTargetState, ColorWrites, CompareFunction, DepthStencilState, FragmentState,
+            LoadOp, MultisampleState, Operations, RenderPassColorAttachment,
+            RenderPassDepthStencilAttachment, RenderPassDescriptor, RenderPipelineDescriptor,
+            ShaderDefVal, SpecializedMeshPipeline, SpecializedMeshPipelineError, StencilState,
+            TextureFormat,
         },
         renderer::RenderContext,
     },
@@ -17,31 +20,112 @@ use bevy::{
 
 use crate::{resources::OutlineResources, MeshMask, MASK_SHADER_HANDLE};
 
+/// Plugin-level outline settings.
+#[derive(Resource, Clone, Copy, Default)]
+pub struct OutlineSettings {
+    /// When enabled, the mask pass depth-tests against the main 3D pass so that
+    /// outlines clip against foreground geometry instead of showing through
+    /// occluders. Disabled by default, which keeps the silhouette-through-walls
+    /// effect as the default behavior.
+    pub depth_test: bool,
+}
+
+/// Which mask variant a mesh is rendered with.
+///
+/// Opaque meshes write coverage directly; alpha-clip meshes sample the material
+/// base-color texture in the fragment shader and discard fragments below a
+/// cutoff, so cutout materials (foliage, chain-link, sprites-on-quads) produce
+/// a correct silhouette instead of a boxy one.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum MaskPassType {
+    Opaque,
+    AlphaClip,
+}
+
+/// Compact, packed specialization key for [`MeshMaskPipeline`].
+///
+/// Mirrors the packing trick [`MeshPipelineKey`] uses: the MSAA sample count
+/// and primitive topology live in the wrapped mesh key (whose high bits hold
+/// them), and two middle bits that the mesh key leaves unused select the mask
+/// pass variant and backend path. Keeping the whole key in a single `u32` keeps
+/// specialization caching cheap.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct MaskPipelineKey(u32);
+
+impl MaskPipelineKey {
+    /// Selects the alpha-clip pass variant.
+    const ALPHA_CLIP_BIT: u32 = 1 << 16;
+    /// Set on the GL/WebGL2 backend, which cannot resolve a multisampled mask
+    /// target and needs a GL-safe, single-sampled path.
+    const GL_BIT: u32 = 1 << 17;
+    const RESERVED_BITS: u32 = Self::ALPHA_CLIP_BIT | Self::GL_BIT;
+
+    pub fn new(mesh_key: MeshPipelineKey, pass_type: MaskPassType, gl: bool) -> Self {
+        let mut bits = mesh_key.bits();
+        if matches!(pass_type, MaskPassType::AlphaClip) {
+            bits |= Self::ALPHA_CLIP_BIT;
+        }
+        if gl {
+            bits |= Self::GL_BIT;
+        }
+        MaskPipelineKey(bits)
+    }
+
+    pub fn pass_type(&self) -> MaskPassType {
+        if self.0 & Self::ALPHA_CLIP_BIT != 0 {
+            MaskPassType::AlphaClip
+        } else {
+            MaskPassType::Opaque
+        }
+    }
+
+    /// Whether this key targets the GL/WebGL2 backend.
+    pub fn is_gl(&self) -> bool {
+        self.0 & Self::GL_BIT != 0
+    }
+
+    pub fn mesh_key(&self) -> MeshPipelineKey {
+        MeshPipelineKey::from_bits_truncate(self.0 & !Self::RESERVED_BITS)
+    }
+}
+
 #[derive(Resource)]
 pub struct MeshMaskPipeline {
     mesh_pipeline: MeshPipeline,
+    /// When set, the mask pass depth-tests against the main 3D pass so that
+    /// occluded fragments are not marked. Silhouette-through-walls (the
+    /// default) leaves this `false`.
+    depth_test: bool,
 }
 
 impl FromWorld for MeshMaskPipeline {
     fn from_world(world: &mut World) -> Self {
         let mesh_pipeline = world.get_resource::<MeshPipeline>().unwrap().clone();
+        let depth_test = world
+            .get_resource::<OutlineSettings>()
+            .map(|settings| settings.depth_test)
+            .unwrap_or(false);
 
-        MeshMaskPipeline { mesh_pipeline }
+        MeshMaskPipeline {
+            mesh_pipeline,
+            depth_test,
+        }
     }
 }
 
 impl SpecializedMeshPipeline for MeshMaskPipeline {
-    type Key = MeshPipelineKey;
+    type Key = MaskPipelineKey;
 
     fn specialize(
         &self,
         key: Self::Key,
         layout: &Hashed<InnerMeshVertexBufferLayout, FixedState>,
     ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
-        let mut desc = self.mesh_pipeline.specialize(key, layout)?;
+        let mesh_key = key.mesh_key();
+        let mut desc = self.mesh_pipeline.specialize(mesh_key, layout)?;
 
         desc.layout = vec![
-            if key.msaa_samples() > 0 {
+            if mesh_key.msaa_samples() > 1 {
                 self.mesh_pipeline.view_layout_multisampled.clone()
             } else {
                 self.mesh_pipeline.view_layout.clone()
@@ -51,29 +135,67 @@ impl SpecializedMeshPipeline for MeshMaskPipeline {
 
         desc.vertex.shader = MASK_SHADER_HANDLE.typed::<Shader>();
 
+        let mut shader_defs = vec![
+            ShaderDefVal::Int(
+                "MAX_DIRECTIONAL_LIGHTS".to_string(),
+                MAX_DIRECTIONAL_LIGHTS as i32,
+            ),
+            ShaderDefVal::Int(
+                "MAX_CASCADES_PER_LIGHT".to_string(),
+                MAX_CASCADES_PER_LIGHT as i32,
+            ),
+        ];
+        // The alpha-clip variant samples the material base-color alpha and
+        // discards below a cutoff in the fragment shader.
+        if matches!(key.pass_type(), MaskPassType::AlphaClip) {
+            shader_defs.push(ShaderDefVal::from("ALPHA_CLIP"));
+        }
+
         desc.fragment = Some(FragmentState {
             shader: MASK_SHADER_HANDLE.typed::<Shader>(),
-            shader_defs: vec![
-                ShaderDefVal::Int(
-                    "MAX_DIRECTIONAL_LIGHTS".to_string(),
-                    MAX_DIRECTIONAL_LIGHTS as i32,
-                ),
-                ShaderDefVal::Int(
-                    "MAX_CASCADES_PER_LIGHT".to_string(),
-                    MAX_CASCADES_PER_LIGHT as i32,
-                ),
-            ],
+            shader_defs,
             entry_point: "fragment".into(),
             targets: vec![Some(ColorTargetState {
-                format: TextureFormat::R8Unorm,
+                // Stores a per-entity outline group ID (0 = no coverage) rather
+                // than plain coverage, so the JFA can carry the ID through to
+                // the composite for per-group colors. WebGL2 drivers are finicky
+                // about wide integer render targets, so the GL backend uses an
+                // 8-bit unsigned integer mask (IDs are clamped to 8 bits there).
+                format: if key.is_gl() {
+                    TextureFormat::R8Uint
+                } else {
+                    TextureFormat::R32Uint
+                },
                 blend: None,
                 write_mask: ColorWrites::ALL,
             })],
         });
-        desc.depth_stencil = None;
+        // Read-only depth test against the main pass's depth buffer. Bevy uses
+        // a reversed-Z depth buffer, so fragments at the rendered scene depth
+        // compare `GreaterEqual`; occluded fragments fail the test and are not
+        // written to the mask.
+        desc.depth_stencil = if self.depth_test {
+            Some(DepthStencilState {
+                format: TextureFormat::Depth32Float,
+                depth_write_enabled: false,
+                depth_compare: CompareFunction::GreaterEqual,
+                stencil: StencilState::default(),
+                bias: default(),
+            })
+        } else {
+            None
+        };
 
+        // The GL/WebGL2 backend cannot resolve a multisampled mask target, so
+        // force the single-sampled (non-resolving) path there. `OutlineResources`
+        // reads the same key bit and allocates a matching single-sampled target,
+        // so the attachment and pipeline always agree.
         desc.multisample = MultisampleState {
-            count: 4,
+            count: if key.is_gl() {
+                1
+            } else {
+                mesh_key.msaa_samples()
+            },
             mask: !0,
             alpha_to_coverage_enabled: false,
         };
@@ -86,21 +208,29 @@ impl SpecializedMeshPipeline for MeshMaskPipeline {
 /// Render graph node for producing stencils from meshes.
 pub struct MeshMaskNode {
     query: QueryState<&'static RenderPhase<MeshMask>>,
+    depth_test: bool,
 }
 
 impl MeshMaskNode {
     pub const IN_VIEW: &'static str = "view";
 
-    /// The produced stencil buffer.
+    /// The produced mask buffer.
     ///
-    /// This has format `TextureFormat::Depth24PlusStencil8`. Fragments covered
-    /// by a mesh are assigned a value of 255. All other fragments are assigned
-    /// a value of 0. The depth aspect is unused.
+    /// This has format `TextureFormat::R32Uint` (`TextureFormat::R8Uint` on the
+    /// GL/WebGL2 backend). Fragments covered by a mesh
+    /// are assigned that mesh's outline group ID (a non-zero `u32` taken from
+    /// its outline component). All other fragments are assigned `0`.
     pub const OUT_MASK: &'static str = "stencil";
 
     pub fn new(world: &mut World) -> MeshMaskNode {
+        let depth_test = world
+            .get_resource::<OutlineSettings>()
+            .map(|settings| settings.depth_test)
+            .unwrap_or(false);
+
         MeshMaskNode {
             query: QueryState::new(world),
+            depth_test,
         }
     }
 }
@@ -126,8 +256,24 @@ impl Node for MeshMaskNode {
     ) -> Result<(), bevy::render::render_graph::NodeRunError> {
         let res = world.get_resource::<OutlineResources>().unwrap();
 
+        // With MSAA enabled the pass renders into the multisampled target and
+        // resolves into `mask_output`. When MSAA is off the target is
+        // single-sampled: the pass renders straight into `mask_output` with no
+        // resolve step. Either way `mask_output` is the texture we publish, so
+        // the JFA always seeds from the texture that was actually written.
+        // `OutlineResources` allocates `mask_multisample` at the view's sample
+        // count, so the attachment and pipeline always agree.
+        let (render_view, resolve_target) = if res.mask_multisample.texture.sample_count() > 1 {
+            (
+                &res.mask_multisample.default_view,
+                Some(&res.mask_output.default_view),
+            )
+        } else {
+            (&res.mask_output.default_view, None)
+        };
+
         graph
-            .set_output(Self::OUT_MASK, res.mask_multisample.default_view.clone())
+            .set_output(Self::OUT_MASK, res.mask_output.default_view.clone())
             .unwrap();
 
         let view_entity = graph.get_input_entity(Self::IN_VIEW).unwrap();
@@ -136,17 +282,34 @@ impl Node for MeshMaskNode {
             Err(_) => return Ok(()),
         };
 
+        // In occlusion mode we depth-test against the main 3D pass, whose depth
+        // texture is attached to the view entity as a `ViewDepthTexture`.
+        let depth = if self.depth_test {
+            world.get::<ViewDepthTexture>(view_entity)
+        } else {
+            None
+        };
+
         let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
             label: Some("outline_stencil_render_pass"),
             color_attachments: &[Some(RenderPassColorAttachment {
-                view: &res.mask_multisample.default_view,
-                resolve_target: Some(&res.mask_output.default_view),
+                view: render_view,
+                resolve_target,
                 ops: Operations {
                     load: LoadOp::Clear(Color::BLACK.into()),
                     store: true,
                 },
             })],
-            depth_stencil_attachment: None,
+            depth_stencil_attachment: depth.map(|depth| RenderPassDepthStencilAttachment {
+                view: &depth.view,
+                // Depth is read-only: load the main pass values and keep them so
+                // the outline test does not disturb the scene.
+                depth_ops: Some(Operations {
+                    load: LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
         });
 
         stencil_phase.render(&mut pass, world, view_entity);